@@ -1,11 +1,28 @@
-use std::collections::HashMap;
-use std::mem;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
 
-use yaml_rust::YamlLoader;
+use comrak::options::Plugins;
+use comrak::plugins::syntect::SyntectAdapter;
+use comrak::{markdown_to_html_with_plugins, Options};
+use handlebars::Handlebars;
+use hyper::Client;
+use hyper::header::Location;
+use indexmap::IndexMap;
+use moka::sync::Cache;
+use serde_derive::{Deserialize, Serialize};
 use hyper;
 
+/// Maximum number of redirects `Weekly::fetch_url` will follow before giving up.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Output backend `Weekly::render` writes through.
+pub enum OutputFormat {
+    Markdown,
+    Html,
+}
+
 pub enum Error {
     ConfigErr,
     RequestErr(hyper::error::Error),
@@ -14,69 +31,138 @@ pub enum Error {
     IOErr,
 }
 
-enum EntryType { Draft, Topic }
+#[derive(Deserialize, Serialize, PartialEq, Default)]
+enum EntryType {
+    #[serde(rename = "draft")]
+    #[default]
+    Draft,
+    #[serde(rename = "topic")]
+    Topic,
+}
 
+#[derive(Deserialize, Serialize)]
 pub struct Entry {
     name: String,
+    // Entry files spell this field `type`, but it's exposed to templates
+    // as `kind` (see `Template`'s doc comment) to match the Rust field name.
+    #[serde(rename(deserialize = "type"), default)]
     kind: EntryType,
     link: Option<String>,
     description: Option<String>,
     quote: Option<String>,
+    #[serde(default)]
     cc: Vec<String>,
     // TODO: tag? keyword?
 }
 
+/// Default base URL contributor handles resolve against when no explicit
+/// mapping is given.
+const DEFAULT_CONTRIBUTOR_BASE_URL: &str = "https://github.com";
+
 pub struct Weekly {
-    entries: HashMap<String, Entry>,
+    entries: IndexMap<String, Entry>,
+    template: Option<Template>,
+    // Caches raw response bodies by URL for up to 10 seconds (at most 100
+    // entries), so duplicate links fetched close together don't re-hit the network.
+    // This cache is in-memory only and does not persist across separate runs.
+    cache: Cache<String, String>,
+    // handle -> profile URL, for resolving `cc` reference-link footers.
+    contributors: IndexMap<String, String>,
+    contributor_base_url: String,
+}
+
+/// A user-supplied Handlebars template for the whole digest. Exposes each
+/// `Entry`'s fields (`name`, `kind`, `link`, `description`, `quote`, `cc`) as
+/// template variables, grouped into `topics` and `drafts` loops, so a project
+/// can restyle headings, bullet style, and cc formatting without recompiling.
+pub struct Template {
+    handlebars: Handlebars<'static>,
+}
+
+impl Template {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Template, Error> {
+        let mut file = File::open(path).map_err(|_| { Error::IOErr })?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|_| { Error::IOErr })?;
+        let mut handlebars = Handlebars::new();
+        // The output is Markdown, not HTML, so `&`/`<`/`>`/`"` in entry
+        // fields must not be turned into HTML entities.
+        handlebars.register_escape_fn(handlebars::no_escape);
+        handlebars.register_template_string("weekly", &text).map_err(|_| { Error::ConfigErr })?;
+        Ok(Template { handlebars })
+    }
+
+    fn render(&self, entries: &IndexMap<String, Entry>) -> Result<String, Error> {
+        let topics: Vec<&Entry> = entries.values().filter(|e| e.kind == EntryType::Topic).collect();
+        let drafts: Vec<&Entry> = entries.values().filter(|e| e.kind == EntryType::Draft).collect();
+        let mut data = serde_json::Map::new();
+        data.insert("topics".to_string(), serde_json::to_value(&topics).map_err(|_| { Error::JsonParseErr })?);
+        data.insert("drafts".to_string(), serde_json::to_value(&drafts).map_err(|_| { Error::JsonParseErr })?);
+        self.handlebars.render("weekly", &serde_json::Value::Object(data)).map_err(|_| { Error::ConfigErr })
+    }
+}
+
+/// The on-disk format an entry (or bundle of entries) is encoded in.
+enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn from_extension(path: &Path) -> Option<Format> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Some(Format::Yaml),
+            Some("toml") => Some(Format::Toml),
+            Some("json") => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Like `from_extension`, but strips a URL's query string and fragment
+    /// first, so `.../entries.yaml?v=1` is still recognised as YAML.
+    fn from_url(url: &str) -> Option<Format> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        Format::from_extension(Path::new(path))
+    }
 }
 
 impl Entry {
-    fn parse(yaml: &str) -> Option<Entry> {
-        YamlLoader::load_from_str(yaml).ok().and_then(|docs| {
-            docs.iter().next().and_then(|doc| {
-                let name = doc["name"].as_str().map(|s| { s.to_string() });
-                let kind = match doc["type"].as_str() {
-                    Some("draft") => Some(EntryType::Draft),
-                    Some("topic") => Some(EntryType::Topic),
-                    Some(_) => None,
-                    None => Some(EntryType::Draft),
-                };
-                let link = doc["link"].as_str().map(|s| { s.to_string() });
-                let description = doc["description"].as_str().map(|s| { s.to_string() });
-                let quote = doc["quote"].as_str().map(|s| { s.to_string() });
-                let mut cc = Vec::new();
-                for person in doc["cc"].as_vec().unwrap_or(&Vec::new()) {
-                    match person.as_str() {
-                        Some(c) => cc.push(c.to_string()),
-                        None => {}
-                    }
-                }
+    fn parse(text: &str, format: Format) -> Result<Entry, Error> {
+        match format {
+            Format::Yaml => serde_yaml::from_str(text).map_err(|_| { Error::ConfigErr }),
+            Format::Toml => toml::from_str(text).map_err(|_| { Error::ConfigErr }),
+            Format::Json => serde_json::from_str(text).map_err(|_| { Error::JsonParseErr }),
+        }
+    }
 
-                match (name, kind) {
-                    (Some(name), Some(kind)) => Some(Entry {
-                        name: name,
-                        kind: kind,
-                        link: link,
-                        description: description,
-                        quote: quote,
-                        cc: cc,
-                    }),
-                    _ => None,
-                }
-            })
+    /// Parses either a single entry or a list of entries, whichever the
+    /// document holds. Remote bundles may contain either shape.
+    fn parse_bundle(text: &str, format: Format) -> Result<Vec<Entry>, Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Bundle {
+            Many(Vec<Entry>),
+            One(Entry),
+        }
+        let bundle = match format {
+            Format::Yaml => serde_yaml::from_str(text).map_err(|_| { Error::ConfigErr }),
+            Format::Toml => toml::from_str(text).map_err(|_| { Error::ConfigErr }),
+            Format::Json => serde_json::from_str(text).map_err(|_| { Error::JsonParseErr }),
+        }?;
+        Ok(match bundle {
+            Bundle::Many(entries) => entries,
+            Bundle::One(entry) => vec![entry],
         })
     }
-    
+
     fn field_append(a: &mut Option<String>, b: &mut Option<String>) {
-        match mem::replace(b, None) {
-            Some(s2) => {
-                if a.is_some() {
-                    a.as_mut().map(|s1| { s1.push_str(&s2) });
-                } else {
-                    mem::replace(a, Some(s2));
-                }
+        if let Some(s2) = b.take() {
+            if let Some(s1) = a.as_mut() {
+                s1.push_str(&s2);
+            } else {
+                *a = Some(s2);
             }
-            None => {}
         }
     }
 
@@ -89,27 +175,24 @@ impl Entry {
         self.cc.append(&mut other.cc);
     }
 
-    fn render(&self, file: &mut File) -> Result<(), Error> {
-        try!(write!(file, "- ").map_err(|_| { Error::IOErr }));
+    fn render<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        write!(w, "- ").map_err(|_| { Error::IOErr })?;
         match self.link.as_ref() {
-            Some(link) => try!(write!(file, "[{}]({})", self.name, link).map_err(|_| { Error::IOErr })),
-            None => try!(write!(file, "{}", self.name).map_err(|_| { Error::IOErr })),
+            Some(link) => write!(w, "[{}]({})", self.name, link).map_err(|_| { Error::IOErr })?,
+            None => write!(w, "{}", self.name).map_err(|_| { Error::IOErr })?,
         }
         match self.description.as_ref() {
-            Some(desc) => try!(write!(file, ", {}\n", desc).map_err(|_| { Error::IOErr })),
-            None => try!(write!(file, "\n").map_err(|_| { Error::IOErr })),
+            Some(desc) => writeln!(w, ", {}", desc).map_err(|_| { Error::IOErr })?,
+            None => writeln!(w).map_err(|_| { Error::IOErr })?,
         }
-        match self.quote.as_ref() {
-            Some(quote) => {
-                for line in quote.lines() {
-                    try!(write!(file, " > {}\n", line).map_err(|_| { Error::IOErr }));
-                }
+        if let Some(quote) = self.quote.as_ref() {
+            for line in quote.lines() {
+                writeln!(w, " > {}", line).map_err(|_| { Error::IOErr })?;
             }
-            None => {}
         }
-        if self.cc.len() > 0 {
+        if !self.cc.is_empty() {
             let cc_list: Vec<_> = self.cc.iter().map(|person| { format!("[@{}][{}]", person, person) }).collect();
-            try!(write!(file, "{}\n", cc_list.join(", ")).map_err(|_| { Error::IOErr }));
+            writeln!(w, "{}", cc_list.join(", ")).map_err(|_| { Error::IOErr })?;
         }
         Ok(())
     }
@@ -118,28 +201,217 @@ impl Entry {
 impl Weekly {
     pub fn new() -> Weekly {
         Weekly {
-            entries: HashMap::new(),
+            entries: IndexMap::new(),
+            template: None,
+            cache: Cache::builder()
+                .max_capacity(100)
+                .time_to_live(Duration::from_secs(10))
+                .build(),
+            contributors: IndexMap::new(),
+            contributor_base_url: DEFAULT_CONTRIBUTOR_BASE_URL.to_string(),
         }
     }
+}
 
-    pub fn parse(&mut self, yaml: &str) {
-        let entry = Entry::parse(yaml);
-        match entry {
-            Some(e) => {
-                if let Some(ent) = self.entries.get_mut(&e.name) {
-                    ent.merge(e);
-                    return;
+impl Default for Weekly {
+    fn default() -> Weekly {
+        Weekly::new()
+    }
+}
+
+impl Weekly {
+    pub fn set_contributor_base_url(&mut self, base_url: &str) {
+        self.contributor_base_url = base_url.to_string();
+    }
+
+    pub fn add_contributor(&mut self, handle: &str, url: &str) {
+        self.contributors.insert(handle.to_string(), url.to_string());
+    }
+
+    pub fn load_contributors<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let format = Format::from_extension(path).ok_or(Error::ConfigErr)?;
+        let mut file = File::open(path).map_err(|_| { Error::IOErr })?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|_| { Error::IOErr })?;
+        let contributors: IndexMap<String, String> = match format {
+            Format::Yaml => serde_yaml::from_str(&text).map_err(|_| { Error::ConfigErr })?,
+            Format::Toml => toml::from_str(&text).map_err(|_| { Error::ConfigErr })?,
+            Format::Json => serde_json::from_str(&text).map_err(|_| { Error::JsonParseErr })?,
+        };
+        self.contributors.extend(contributors);
+        Ok(())
+    }
+
+    fn contributor_url(&self, handle: &str) -> String {
+        match self.contributors.get(handle) {
+            Some(url) => url.clone(),
+            None => format!("{}/{}", self.contributor_base_url, handle),
+        }
+    }
+
+    fn render_contributor_footer(&self) -> String {
+        let mut handles: Vec<&String> = Vec::new();
+        for entry in self.entries.values() {
+            for handle in &entry.cc {
+                if !handles.contains(&handle) {
+                    handles.push(handle);
                 }
-                self.entries.insert(e.name.clone(), e);
             }
-            None => {},
         }
+        let mut footer = String::new();
+        for handle in handles {
+            footer.push_str(&format!("[{}]: {}\n", handle, self.contributor_url(handle)));
+        }
+        footer
     }
 
-    pub fn render(&self, mut file: File) -> Result<(), Error> {
-        for entry in self.entries.values() {
-            try!(entry.render(&mut file));
+    pub fn load_template<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.template = Some(Template::load(path)?);
+        Ok(())
+    }
+
+    fn add_entry(&mut self, entry: Entry) {
+        if let Some(ent) = self.entries.get_mut(&entry.name) {
+            ent.merge(entry);
+            return;
         }
+        self.entries.insert(entry.name.clone(), entry);
+    }
+
+    pub fn parse(&mut self, yaml: &str) -> Result<(), Error> {
+        let entry = Entry::parse(yaml, Format::Yaml)?;
+        self.add_entry(entry);
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn parse_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let format = Format::from_extension(path).ok_or(Error::ConfigErr)?;
+        let mut file = File::open(path).map_err(|_| { Error::IOErr })?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|_| { Error::IOErr })?;
+        let entry = Entry::parse(&text, format)?;
+        self.add_entry(entry);
+        Ok(())
+    }
+
+    fn fetch_url(&self, url: &str) -> Result<String, Error> {
+        if let Some(body) = self.cache.get(url) {
+            return Ok(body);
+        }
+        let client = Client::new();
+        let mut current = url.to_string();
+        let mut redirects = 0;
+        let body = loop {
+            let mut response = client.get(&current).send().map_err(Error::RequestErr)?;
+            if response.status.is_redirection() {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(Error::FetchErr);
+                }
+                current = match response.headers.get::<Location>() {
+                    Some(location) => location.to_string(),
+                    None => return Err(Error::FetchErr),
+                };
+                continue;
+            }
+            if !response.status.is_success() {
+                return Err(Error::FetchErr);
+            }
+            let mut body = String::new();
+            response.read_to_string(&mut body).map_err(|_| { Error::IOErr })?;
+            break body;
+        };
+        self.cache.insert(url.to_string(), body.clone());
+        Ok(body)
+    }
+
+    /// Downloads a remote YAML/JSON entry bundle and merges it in, the same
+    /// way a locally parsed file would be.
+    pub fn fetch(&mut self, url: &str) -> Result<(), Error> {
+        let body = self.fetch_url(url)?;
+        let format = Format::from_url(url).ok_or(Error::ConfigErr)?;
+        for entry in Entry::parse_bundle(&body, format)? {
+            self.add_entry(entry);
+        }
+        Ok(())
+    }
+
+    /// Finds the content of the page's `<title>` tag, if any. Matching is
+    /// case-insensitive and tolerates attributes on the opening tag (e.g.
+    /// `<title lang="en">`), since not every page we fetch spells it exactly
+    /// like the HTML spec's example does.
+    fn resolve_title(&self, url: &str) -> Option<String> {
+        let body = match self.fetch_url(url) {
+            Ok(body) => body,
+            Err(_) => return None,
+        };
+        let lower = body.to_ascii_lowercase();
+        let tag_start = lower.find("<title")?;
+        let open_end = tag_start + lower[tag_start..].find('>')? + 1;
+        let close_start = open_end + lower[open_end..].find("</title")?;
+        Some(body[open_end..close_start].trim().to_string())
+    }
+
+    /// For every entry with a link but no description, fetch the linked page
+    /// and use its `<title>` as the description. A dead link or missing
+    /// title is skipped rather than aborting the whole digest.
+    pub fn resolve_descriptions(&mut self) {
+        let pending: Vec<(String, String)> = self.entries.values()
+            .filter(|e| e.description.is_none())
+            .filter_map(|e| e.link.clone().map(|link| (e.name.clone(), link)))
+            .collect();
+        for (name, link) in pending {
+            if let Some(title) = self.resolve_title(&link) {
+                if let Some(entry) = self.entries.get_mut(&name) {
+                    entry.description = Some(title);
+                }
+            }
+        }
+    }
+
+    fn render_markdown(&self) -> Result<String, Error> {
+        let mut body = if let Some(ref template) = self.template {
+            template.render(&self.entries)?
+        } else {
+            let mut buf: Vec<u8> = Vec::new();
+            write!(buf, "## Topics\n\n").map_err(|_| { Error::IOErr })?;
+            for entry in self.entries.values().filter(|e| e.kind == EntryType::Topic) {
+                entry.render(&mut buf)?;
+            }
+            write!(buf, "\n## Drafts\n\n").map_err(|_| { Error::IOErr })?;
+            for entry in self.entries.values().filter(|e| e.kind == EntryType::Draft) {
+                entry.render(&mut buf)?;
+            }
+            String::from_utf8(buf).map_err(|_| { Error::IOErr })?
+        };
+        let footer = self.render_contributor_footer();
+        if !footer.is_empty() {
+            body.push('\n');
+            body.push_str(&footer);
+        }
+        Ok(body)
+    }
+
+    fn render_html(markdown: &str) -> String {
+        let adapter = SyntectAdapter::new(Some("InspiredGitHub"));
+        let options = Options::default();
+        let mut plugins = Plugins::default();
+        plugins.render.codefence_syntax_highlighter = Some(&adapter);
+        let body = markdown_to_html_with_plugins(markdown, &options, &plugins);
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"></head>\n<body>\n{}</body>\n</html>\n",
+            body
+        )
+    }
+
+    pub fn render(&self, mut file: File, format: OutputFormat) -> Result<(), Error> {
+        let markdown = self.render_markdown()?;
+        let output = match format {
+            OutputFormat::Markdown => markdown,
+            OutputFormat::Html => Self::render_html(&markdown),
+        };
+        file.write_all(output.as_bytes()).map_err(|_| { Error::IOErr })
+    }
+}